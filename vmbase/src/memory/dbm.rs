@@ -12,30 +12,69 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Hardware management of the access flag and dirty state.
+//! Hardware management of the access flag and dirty state, with a software-emulated fallback
+//! for CPUs that don't implement HAFDBS.
 
 use super::page_table::is_leaf_pte;
 use super::util::flush_region;
 use crate::{isb, read_sysreg, write_sysreg};
 use aarch64_paging::paging::{Attributes, Descriptor, MemoryRegion};
+use core::arch::asm;
 
-/// Sets whether the hardware management of access and dirty state is enabled with
-/// the given boolean.
+// TCR_EL1.{HA,HD} bits controlling hardware management of access and dirty state.
+const TCR_EL1_HA_HD_BITS: usize = 3 << 39;
+
+/// Sets whether the hardware management of access and dirty state is enabled on the current CPU.
+///
+/// Safe to call on a mix of HAFDBS-capable and incapable CPUs, and to toggle dirty logging on
+/// and off at runtime: enabling re-checks `dbm_available()` on the current core, and disabling
+/// always follows through with the TLB maintenance needed to retire any stale hardware-managed
+/// translations.
 pub fn set_dbm_enabled(enabled: bool) {
+    if enabled {
+        enable_dbm_on_current_cpu();
+    } else {
+        disable_dbm_on_current_cpu();
+    }
+}
+
+/// Enables hardware management of access and dirty state on the current CPU, if HAFDBS is
+/// available here.
+///
+/// Must be called on each CPU individually during bring-up rather than assumed from the boot
+/// CPU's support, since HAFDBS is a per-core property and a late-booting secondary CPU isn't
+/// guaranteed to match it.
+fn enable_dbm_on_current_cpu() {
     if !dbm_available() {
         return;
     }
-    // TCR_EL1.{HA,HD} bits controlling hardware management of access and dirty state
-    const TCR_EL1_HA_HD_BITS: usize = 3 << 39;
+    let mut tcr = read_sysreg!("tcr_el1");
+    tcr |= TCR_EL1_HA_HD_BITS;
+    // Safe because it writes to a system register and does not affect Rust.
+    unsafe { write_sysreg!("tcr_el1", tcr) };
+    isb!();
+}
 
+/// Disables hardware management of access and dirty state on the current CPU.
+///
+/// The TLB can cache translations tagged with the old TCR_EL1.{HA,HD} setting, so clearing the
+/// bits on their own isn't enough: this also performs a full local TLB invalidation, so no
+/// hardware-managed translation outlives the switch back to software-managed (or no) dirty
+/// tracking. Always runs this maintenance, even if HAFDBS isn't available here, since this is
+/// also the path used to turn dirty logging off again after `DirtyBitmap` re-armed it.
+fn disable_dbm_on_current_cpu() {
     let mut tcr = read_sysreg!("tcr_el1");
-    if enabled {
-        tcr |= TCR_EL1_HA_HD_BITS
-    } else {
-        tcr &= !TCR_EL1_HA_HD_BITS
-    };
+    tcr &= !TCR_EL1_HA_HD_BITS;
     // Safe because it writes to a system register and does not affect Rust.
-    unsafe { write_sysreg!("tcr_el1", tcr) }
+    unsafe { write_sysreg!("tcr_el1", tcr) };
+    isb!();
+
+    // SAFETY: Plain barrier and TLB maintenance instructions, affecting only CPU/MMU state.
+    unsafe {
+        asm!("dsb ishst");
+        asm!("tlbi vmalle1");
+        asm!("dsb ish");
+    }
     isb!();
 }
 
@@ -49,6 +88,45 @@ fn dbm_available() -> bool {
     read_sysreg!("id_aa64mmfr1_el1") & DBM_AVAILABLE != 0
 }
 
+/// Returns `true` if hardware dirty state management is currently switched on on this CPU, i.e.
+/// `enable_dbm_on_current_cpu()` has taken effect here and `disable_dbm_on_current_cpu()` hasn't
+/// since undone it.
+///
+/// This is distinct from `dbm_available()`, which only reflects whether the hardware is capable
+/// of HAFDBS, not whether it's presently managing access/dirty state: `set_dbm_enabled(false)`
+/// can turn hardware management back off on a capable CPU (e.g. to stop migration tracking), and
+/// every decision about how a PTE's dirty state is recorded must follow that switch, not just the
+/// capability. Reads TCR_EL1.{HA,HD} directly rather than caching a flag, since TCR_EL1 is itself
+/// banked per-CPU, so each core's own enable/disable calls are automatically reflected here.
+fn dbm_enabled() -> bool {
+    read_sysreg!("tcr_el1") & TCR_EL1_HA_HD_BITS == TCR_EL1_HA_HD_BITS
+}
+
+/// Software-managed dirty bit, used in place of hardware dirty state management whenever it isn't
+/// enabled on the faulting CPU, whether because the CPU lacks HAFDBS or because it's been switched
+/// off with `set_dbm_enabled(false)`.
+///
+/// This reuses one of the PTE bits in the software-defined, hardware-ignored range (bit 55), so
+/// it can coexist with the hardware AP[2] (READ_ONLY) encoding that `flush_dirty_range()` also
+/// inspects.
+const SW_DIRTY: Attributes = Attributes::from_bits_retain(1 << 55);
+
+/// Returns `true` if a leaf PTE with the given flags should be considered dirty, i.e. it has been
+/// written to since it was last cleaned.
+///
+/// `!READ_ONLY` catches PTEs currently under hardware dirty-state management, and `SW_DIRTY`
+/// catches ones `handle_permission_fault()`'s software fallback marked dirty. Deliberately does
+/// *not* consult the executing CPU's own `dbm_enabled()`: this module supports a mix of
+/// HAFDBS-capable and incapable CPUs sharing the same page tables, so the CPU evaluating this
+/// (e.g. a `DirtyBitmap` scan) may have different, or differently-enabled, HAFDBS support than
+/// whichever CPU last dirtied the page. `SW_DIRTY` is only ever set together with clearing
+/// READ_ONLY, and only ever cleared together with re-setting READ_ONLY, so its mere presence
+/// already reflects the dirtying CPU's own decision at the time, and doesn't need to be
+/// re-derived from the scanning CPU's current state.
+fn is_dirty(flags: &Attributes) -> bool {
+    !flags.contains(Attributes::READ_ONLY) || flags.contains(SW_DIRTY)
+}
+
 /// Flushes a memory range the descriptor refers to, if the descriptor is in writable-dirty state.
 /// As the return type is required by the crate `aarch64_paging`, we cannot address the lint
 /// issue `clippy::result_unit_err`.
@@ -63,8 +141,283 @@ pub fn flush_dirty_range(
     if !is_leaf_pte(&flags, level) {
         return Ok(());
     }
-    if !flags.contains(Attributes::READ_ONLY) {
+    if is_dirty(&flags) {
         flush_region(va_range.start().0, va_range.len());
     }
     Ok(())
+}
+
+/// Handles a write-permission fault on a leaf PTE by marking it dirty.
+///
+/// This is the software fallback for whenever hardware dirty state management isn't currently
+/// managing this PTE, whether because the CPU can't do HAFDBS at all or because it's been
+/// switched off with `set_dbm_enabled(false)`: such PTEs are mapped READ_ONLY with no hardware
+/// managing that bit, so the first write to one faults here, and this clears READ_ONLY and sets
+/// `SW_DIRTY` so the page is treated as dirty by `flush_dirty_range()` from then on. Does nothing
+/// while hardware dirty state management is enabled, since the hardware handles this case on its
+/// own.
+///
+/// Uses `atomic_modify_flags()` rather than a plain read-modify-write, since `DirtyBitmap` and
+/// `AccessedBitmap` may be concurrently running their own `atomic_modify_flags()` update against
+/// this same descriptor (e.g. a migration scan on another core); a non-atomic update here
+/// wouldn't participate in their exclusive-monitor protocol and could silently clobber, or be
+/// clobbered by, their change. Also invalidates the TLB entry for the faulting VA before
+/// returning, so a stale read-only translation (on this core or another one sharing the address
+/// space) can't cause the retried store to spuriously fault again.
+///
+/// As with `flush_dirty_range()`, the `Result<(), ()>` is required by `aarch64_paging`.
+#[allow(clippy::result_unit_err)]
+pub fn handle_permission_fault(
+    va_range: &MemoryRegion,
+    desc: &mut Descriptor,
+    level: usize,
+) -> Result<(), ()> {
+    if dbm_enabled() {
+        return Ok(());
+    }
+    let flags = desc.flags().ok_or(())?;
+    if !is_leaf_pte(&flags, level) {
+        return Ok(());
+    }
+    atomic_modify_flags(desc, SW_DIRTY, Attributes::READ_ONLY);
+    invalidate_tlb_range(va_range);
+    Ok(())
+}
+
+/// Atomically sets the bits in `set` and clears the bits in `clear` of the 64-bit PTE at `pte`,
+/// using a load-exclusive/store-exclusive loop.
+///
+/// # Safety
+///
+/// `pte` must be a valid, aligned pointer to a live page table entry for the duration of the
+/// call.
+unsafe fn atomic_modify_pte_bits(pte: *mut u64, set: u64, clear: u64) {
+    loop {
+        let failed: i32;
+        // SAFETY: The caller guarantees that `pte` is a valid, aligned pointer to a live PTE.
+        // ldxr/stxr don't dereference `pte` as a regular Rust reference, so there's no aliasing
+        // concern despite the existence of a live `&mut Descriptor` to the same memory.
+        unsafe {
+            asm!(
+                "ldxr {old}, [{pte}]",
+                "bic {old}, {old}, {clear}",
+                "orr {old}, {old}, {set}",
+                "stxr {failed:w}, {old}, [{pte}]",
+                old = out(reg) _,
+                failed = out(reg) failed,
+                pte = in(reg) pte,
+                clear = in(reg) clear,
+                set = in(reg) set,
+            );
+        }
+        if failed == 0 {
+            break;
+        }
+    }
+}
+
+/// Atomically updates `desc`'s flags, setting the bits in `set` and clearing the bits in `clear`,
+/// using the exclusive monitor rather than a plain read-modify-write.
+///
+/// When HAFDBS is active the CPU can concurrently and asynchronously set the Access Flag and
+/// clear AP[2] (READ_ONLY) on any writable-DBM leaf PTE, racing with a software update of the
+/// same descriptor. A plain load/modify/store could silently revert whichever hardware-set bit
+/// landed between the load and the store; this function instead retries the whole
+/// load-modify-store on a failed store-exclusive, so the hardware's update is never lost. Must be
+/// used for all descriptor mutations while `dbm_enabled()` is true.
+pub fn atomic_modify_flags(desc: &mut Descriptor, set: Attributes, clear: Attributes) {
+    let pte = desc as *mut Descriptor as *mut u64;
+    // SAFETY: `desc` is a `&mut Descriptor` referring to a live leaf PTE in the active page
+    // table, so its address is a valid, aligned pointer to the underlying 64-bit descriptor for
+    // as long as we hold the exclusive reference.
+    unsafe { atomic_modify_pte_bits(pte, set.bits() as u64, clear.bits() as u64) };
+}
+
+/// Size in bytes of the leaf pages this module tracks dirtiness for.
+const PAGE_SIZE: usize = 4096;
+
+/// Returns the index of the page containing `va` within a bitmap whose region starts at `base`,
+/// or `Err(())` if `va` lies before `base`.
+fn page_index(va: usize, base: usize) -> Result<usize, ()> {
+    Ok(va.checked_sub(base).ok_or(())? / PAGE_SIZE)
+}
+
+/// Returns the `(word, bit)` position of `page` within a bitmap of `u64` words, one bit per page.
+fn bitmap_word_and_bit(page: usize) -> (usize, usize) {
+    (page / u64::BITS as usize, page % u64::BITS as usize)
+}
+
+/// Invalidates any TLB entries for `va_range` and ensures the preceding store to its PTE is
+/// visible to the page table walker before returning, so no access to the range goes on using
+/// the old translation (e.g. no in-flight write is lost once a re-protected page is considered
+/// clean).
+fn invalidate_tlb_range(va_range: &MemoryRegion) {
+    // SAFETY: Plain barrier and TLB maintenance instructions, affecting only CPU/MMU state.
+    unsafe { asm!("dsb ishst") };
+    let mut va = va_range.start().0;
+    while va < va_range.end().0 {
+        // SAFETY: As above.
+        unsafe { asm!("tlbi vaae1is, {}", in(reg) va >> 12) };
+        va += PAGE_SIZE;
+    }
+    // SAFETY: As above.
+    unsafe { asm!("dsb ish") };
+    isb!();
+}
+
+/// A dirty-page bitmap over some guest memory region, filled in by walking the region's leaf
+/// PTEs with [`DirtyBitmap::visit`].
+///
+/// This is the core primitive for iterative pre-copy migration: each pass walks the region,
+/// records which pages were written since the last pass, and (in collect-and-clear mode)
+/// re-protects them so the next pass only reports the delta.
+pub struct DirtyBitmap<'a> {
+    /// One bit per page in the walked region; bit `n` set means page `n` was found dirty.
+    bits: &'a mut [u64],
+    /// Address of the start of the region the bitmap covers.
+    base: usize,
+    /// If true, each dirty page found is also reset to clean ("collect-and-clear"). If false,
+    /// pages are left untouched ("peek").
+    clear: bool,
+}
+
+impl<'a> DirtyBitmap<'a> {
+    /// Creates a bitmap covering the region starting at `base`, to be populated by walking that
+    /// region's leaf PTEs through repeated calls to `visit()`.
+    pub fn new(bits: &'a mut [u64], base: usize, clear: bool) -> Self {
+        Self { bits, base, clear }
+    }
+
+    /// Visits a leaf PTE found during the walk: if it's in writable-dirty state, records it in
+    /// the bitmap and, in collect-and-clear mode, resets it to clean.
+    ///
+    /// Matches the leaf-PTE visitor signature used by `flush_dirty_range()`, so it can be passed
+    /// directly as the callback for a walk over the same region.
+    #[allow(clippy::result_unit_err)]
+    pub fn visit(
+        &mut self,
+        va_range: &MemoryRegion,
+        desc: &mut Descriptor,
+        level: usize,
+    ) -> Result<(), ()> {
+        let flags = desc.flags().ok_or(())?;
+        if !is_leaf_pte(&flags, level) || !is_dirty(&flags) {
+            return Ok(());
+        }
+
+        let page = page_index(va_range.start().0, self.base)?;
+        let (word_idx, bit) = bitmap_word_and_bit(page);
+        let word = self.bits.get_mut(word_idx).ok_or(())?;
+        *word |= 1 << bit;
+
+        if self.clear {
+            // Re-protect the page read-only and, if hardware dirty state management is currently
+            // enabled, re-arm DBM so the next write clears it again; SW_DIRTY is only ever
+            // cleared together with READ_ONLY, since otherwise it would be the sole record of
+            // dirtiness while hardware management isn't in charge of this PTE.
+            let rearm = if dbm_enabled() { Attributes::DBM } else { Attributes::empty() };
+            atomic_modify_flags(desc, Attributes::READ_ONLY | rearm, SW_DIRTY);
+            invalidate_tlb_range(va_range);
+        }
+        Ok(())
+    }
+}
+
+/// A young-page scan over some guest memory region, filled in by walking the region's leaf PTEs
+/// through repeated calls to [`AccessedBitmap::scan_accessed_range`].
+///
+/// This complements `DirtyBitmap`: HAFDBS also hardware-sets the Access Flag on any read or
+/// write to a leaf PTE, so it tracks how recently a page was touched at all, letting a pVM host
+/// or guest identify cold pages for ballooning/reclaim without conflating reads with the
+/// write-dirty state `DirtyBitmap` and `flush_dirty_range()` report.
+pub struct AccessedBitmap<'a> {
+    /// One bit per page in the walked region; bit `n` set means page `n` was found accessed.
+    bits: &'a mut [u64],
+    /// Address of the start of the region the bitmap covers.
+    base: usize,
+    /// If true, AF is cleared on each accessed page found, so a later scan measures the access
+    /// rate over the interval since this one.
+    clear: bool,
+}
+
+impl<'a> AccessedBitmap<'a> {
+    /// Creates a scan covering the region starting at `base`, to be populated by walking that
+    /// region's leaf PTEs through repeated calls to `scan_accessed_range()`.
+    pub fn new(bits: &'a mut [u64], base: usize, clear: bool) -> Self {
+        Self { bits, base, clear }
+    }
+
+    /// Visits a leaf PTE found during the walk: if its Access Flag is set, records it in the
+    /// bitmap and, if requested, clears AF.
+    ///
+    /// AF is managed independently of the READ_ONLY/DBM dirty encoding, so this never disturbs
+    /// the dirty state `DirtyBitmap` and `flush_dirty_range()` rely on.
+    ///
+    /// Matches the leaf-PTE visitor signature used by `flush_dirty_range()`, so it can be passed
+    /// directly as the callback for a walk over the same region.
+    #[allow(clippy::result_unit_err)]
+    pub fn scan_accessed_range(
+        &mut self,
+        va_range: &MemoryRegion,
+        desc: &mut Descriptor,
+        level: usize,
+    ) -> Result<(), ()> {
+        let flags = desc.flags().ok_or(())?;
+        if !is_leaf_pte(&flags, level) || !flags.contains(Attributes::ACCESSED) {
+            return Ok(());
+        }
+
+        let page = page_index(va_range.start().0, self.base)?;
+        let (word_idx, bit) = bitmap_word_and_bit(page);
+        let word = self.bits.get_mut(word_idx).ok_or(())?;
+        *word |= 1 << bit;
+
+        if self.clear {
+            atomic_modify_flags(desc, Attributes::empty(), Attributes::ACCESSED);
+            invalidate_tlb_range(va_range);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_dirty_combinations() {
+        let writable = Attributes::empty();
+        let read_only = Attributes::READ_ONLY;
+
+        // Writable (not READ_ONLY) is always dirty, regardless of SW_DIRTY.
+        assert!(is_dirty(&writable));
+        assert!(is_dirty(&(writable | SW_DIRTY)));
+
+        // READ_ONLY with no SW_DIRTY: clean.
+        assert!(!is_dirty(&read_only));
+
+        // READ_ONLY with SW_DIRTY: dirty regardless of the scanning CPU's own DBM state, since
+        // SW_DIRTY alone already records the dirtying CPU's decision.
+        assert!(is_dirty(&(read_only | SW_DIRTY)));
+    }
+
+    #[test]
+    fn page_index_within_region() {
+        assert_eq!(page_index(0x1000, 0x1000), Ok(0));
+        assert_eq!(page_index(0x2000, 0x1000), Ok(1));
+        assert_eq!(page_index(0x1fff, 0x1000), Ok(0));
+    }
+
+    #[test]
+    fn page_index_before_base_is_err() {
+        assert_eq!(page_index(0x1000, 0x2000), Err(()));
+    }
+
+    #[test]
+    fn bitmap_word_and_bit_positions() {
+        assert_eq!(bitmap_word_and_bit(0), (0, 0));
+        assert_eq!(bitmap_word_and_bit(63), (0, 63));
+        assert_eq!(bitmap_word_and_bit(64), (1, 0));
+        assert_eq!(bitmap_word_and_bit(130), (2, 2));
+    }
 }
\ No newline at end of file